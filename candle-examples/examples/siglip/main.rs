@@ -9,7 +9,8 @@ use clap::Parser;
 
 use candle::{DType, Device, Tensor};
 use candle_nn::{ops::softmax, VarBuilder};
-use candle_transformers::models::siglip;
+use candle_transformers::models::{quantized_siglip, siglip};
+use candle_transformers::quantized_var_builder::VarBuilder as QuantizedVarBuilder;
 
 use tokenizers::Tokenizer;
 
@@ -31,6 +32,31 @@ enum Which {
     V2LargePatch16_384,
     #[value(name = "v2-large-patch16-512")]
     V2LargePatch16_512,
+    #[value(name = "v2-base-patch16-naflex")]
+    V2BasePatch16Naflex,
+}
+
+impl Which {
+    fn is_naflex(&self) -> bool {
+        matches!(self, Which::V2BasePatch16Naflex)
+    }
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum, PartialEq, Eq)]
+enum DTypeArg {
+    F32,
+    F16,
+    Bf16,
+}
+
+impl From<DTypeArg> for DType {
+    fn from(dtype: DTypeArg) -> Self {
+        match dtype {
+            DTypeArg::F32 => DType::F32,
+            DTypeArg::F16 => DType::F16,
+            DTypeArg::Bf16 => DType::BF16,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -61,9 +87,54 @@ struct Args {
 
     #[arg(short, long)]
     image_size: Option<usize>,
+
+    /// Maximum sequence length of patches fed to a NaFlex model, only used
+    /// with `--which v2-base-patch16-naflex`. Images are rescaled, preserving
+    /// their aspect ratio, so that `ceil(H/P) * ceil(W/P) <= max-num-patches`.
+    #[arg(long, default_value_t = 256)]
+    max_num_patches: usize,
+
+    /// Run a quantized GGUF checkpoint (q4_0/q4_k/q8_0/...) instead of the
+    /// full-precision safetensors weights. Pass the `.gguf` file via
+    /// `--model`.
+    #[arg(long)]
+    gguf: bool,
+
+    /// Compute dtype for the (non-quantized) model weights and image tensors.
+    /// The logit-scale/bias application and the final softmax always run in
+    /// f32 regardless of this setting, to keep reduced-precision inference
+    /// numerically stable.
+    #[arg(long, value_enum, default_value_t = DTypeArg::F32)]
+    dtype: DTypeArg,
+
+    /// Walk this directory, embed every image found in it, and write the
+    /// resulting index to `--index-out` instead of running zero-shot
+    /// classification.
+    #[arg(long)]
+    build_index: Option<String>,
+
+    /// Output path for `--build-index`.
+    #[arg(long, default_value = "embeddings.bin")]
+    index_out: String,
+
+    /// Path to an index produced by `--build-index`, to search with `--query`.
+    #[arg(long)]
+    index: Option<String>,
+
+    /// Text query to embed and search `--index` for.
+    #[arg(long)]
+    query: Option<String>,
+
+    /// Number of results to print for `--query`.
+    #[arg(long, default_value_t = 5)]
+    top_k: usize,
 }
 
-fn load_image<T: AsRef<std::path::Path>>(path: T, image_size: usize) -> anyhow::Result<Tensor> {
+fn load_image<T: AsRef<std::path::Path>>(
+    path: T,
+    image_size: usize,
+    dtype: DType,
+) -> anyhow::Result<Tensor> {
     let img = image::ImageReader::open(path)?.decode()?;
     let (height, width) = (image_size, image_size);
     let img = img.resize_to_fill(
@@ -76,23 +147,228 @@ fn load_image<T: AsRef<std::path::Path>>(path: T, image_size: usize) -> anyhow::
     let img = Tensor::from_vec(img, (height, width, 3), &Device::Cpu)?
         .permute((2, 0, 1))?
         .to_dtype(DType::F32)?
-        .affine(2. / 255., -1.)?;
+        .affine(2. / 255., -1.)?
+        .to_dtype(dtype)?;
     Ok(img)
 }
 
 fn load_images<T: AsRef<std::path::Path>>(
     paths: &Vec<T>,
     image_size: usize,
+    dtype: DType,
 ) -> anyhow::Result<Tensor> {
     let mut images = vec![];
     for path in paths {
-        let tensor = load_image(path, image_size)?;
+        let tensor = load_image(path, image_size, dtype)?;
         images.push(tensor);
     }
     let images = Tensor::stack(&images, 0)?;
     Ok(images)
 }
 
+/// Picks the largest `(rows, cols)` patch grid, in multiples of `patch_size`,
+/// that preserves `height / width` and fits within `ceil(rows) * ceil(cols)
+/// <= max_num_patches`, as used by the SigLIP2 NaFlex preprocessor. The image
+/// is scaled by at most 8x, so a very small image can get a grid smaller than
+/// the largest one that would fit.
+fn naflex_patch_grid(
+    height: usize,
+    width: usize,
+    patch_size: usize,
+    max_num_patches: usize,
+) -> (usize, usize) {
+    let (height, width) = (height as f64, width as f64);
+    let (mut lo, mut hi) = (1e-3f64, 8.0f64);
+    for _ in 0..30 {
+        let mid = (lo + hi) / 2.0;
+        let rows = ((height * mid) / patch_size as f64).ceil().max(1.0);
+        let cols = ((width * mid) / patch_size as f64).ceil().max(1.0);
+        if rows * cols <= max_num_patches as f64 {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let rows = (((height * lo) / patch_size as f64).ceil() as usize).max(1);
+    let cols = (((width * lo) / patch_size as f64).ceil() as usize).max(1);
+    (rows, cols)
+}
+
+/// Loads an image for the NaFlex path: resizes it to the closest aspect-ratio
+/// preserving `rows x cols` patch grid and slices it into a row-major
+/// sequence of channel-last `patch_size x patch_size x 3` patches, each
+/// flattened to a `patch_size^2 * 3` vector.
+fn load_image_naflex<T: AsRef<std::path::Path>>(
+    path: T,
+    patch_size: usize,
+    max_num_patches: usize,
+    dtype: DType,
+) -> anyhow::Result<(Tensor, usize, usize)> {
+    let img = image::ImageReader::open(path)?.decode()?;
+    let (rows, cols) = naflex_patch_grid(
+        img.height() as usize,
+        img.width() as usize,
+        patch_size,
+        max_num_patches,
+    );
+    let (height, width) = (rows * patch_size, cols * patch_size);
+    let img = img
+        .resize_exact(width as u32, height as u32, image::imageops::FilterType::Triangle)
+        .to_rgb8();
+    let img = img.into_raw();
+    // Kept as (height, width, channels), normalized like `load_image`.
+    let img = Tensor::from_vec(img, (height, width, 3), &Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .affine(2. / 255., -1.)?
+        .to_dtype(dtype)?;
+    let patch_dim = 3 * patch_size * patch_size;
+    let mut patches = Vec::with_capacity(rows * cols);
+    for r in 0..rows {
+        for c in 0..cols {
+            let patch = img
+                .narrow(0, r * patch_size, patch_size)?
+                .narrow(1, c * patch_size, patch_size)?
+                .reshape(patch_dim)?;
+            patches.push(patch);
+        }
+    }
+    let patches = Tensor::stack(&patches, 0)?;
+    Ok((patches, rows, cols))
+}
+
+/// Batches a set of NaFlex images, zero-padding every patch sequence up to
+/// the longest one and returning the padded patches, a boolean validity mask,
+/// and each image's `(rows, cols)` patch grid.
+fn load_images_naflex<T: AsRef<std::path::Path>>(
+    paths: &Vec<T>,
+    patch_size: usize,
+    max_num_patches: usize,
+    dtype: DType,
+) -> anyhow::Result<(Tensor, Tensor, Vec<(usize, usize)>)> {
+    let mut per_image = Vec::with_capacity(paths.len());
+    let mut spatial_shapes = Vec::with_capacity(paths.len());
+    let mut max_len = 0;
+    for path in paths {
+        let (patches, rows, cols) = load_image_naflex(path, patch_size, max_num_patches, dtype)?;
+        max_len = max_len.max(rows * cols);
+        spatial_shapes.push((rows, cols));
+        per_image.push(patches);
+    }
+    let mut padded = Vec::with_capacity(per_image.len());
+    let mut masks = Vec::with_capacity(per_image.len());
+    for patches in per_image {
+        let seq_len = patches.dim(0)?;
+        let mask = Tensor::cat(
+            &[
+                Tensor::ones(seq_len, DType::U8, &Device::Cpu)?,
+                Tensor::zeros(max_len - seq_len, DType::U8, &Device::Cpu)?,
+            ],
+            0,
+        )?;
+        let patches = patches.pad_with_zeros(0, 0, max_len - seq_len)?;
+        padded.push(patches.unsqueeze(0)?);
+        masks.push(mask.unsqueeze(0)?);
+    }
+    let patches = Tensor::cat(&padded, 0)?;
+    let masks = Tensor::cat(&masks, 0)?;
+    Ok((patches, masks, spatial_shapes))
+}
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "bmp", "webp", "gif"];
+
+/// Recursively collects every file under `dir` with a recognized image
+/// extension, depth-first, in directory-listing order.
+fn collect_image_paths(dir: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            paths.extend(collect_image_paths(&path)?);
+        } else if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            if IMAGE_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+                paths.push(path.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Number of images embedded per forward pass in [`build_index`]. Keeps peak
+/// memory bounded regardless of how many images are under the indexed
+/// directory, at the cost of one forward pass per batch instead of one total.
+const INDEX_BATCH_SIZE: usize = 64;
+
+/// Walks `dir`, embeds every image found in it with `model`, and writes the
+/// L2-normalized embedding matrix plus the source paths to a safetensors file
+/// at `index_out`.
+fn build_index(
+    model: &siglip::Model,
+    dir: &str,
+    image_size: usize,
+    dtype: DType,
+    device: &Device,
+    index_out: &str,
+) -> anyhow::Result<()> {
+    let paths = collect_image_paths(std::path::Path::new(dir))?;
+    if paths.is_empty() {
+        anyhow::bail!("no images found under {dir}");
+    }
+    let mut batch_embeddings = Vec::with_capacity(paths.len().div_ceil(INDEX_BATCH_SIZE));
+    for batch in paths.chunks(INDEX_BATCH_SIZE) {
+        let images = load_images(&batch.to_vec(), image_size, dtype)?.to_device(device)?;
+        batch_embeddings.push(model.image_features(&images)?);
+    }
+    let embeddings = Tensor::cat(&batch_embeddings, 0)?;
+    let mut tensors = std::collections::HashMap::new();
+    tensors.insert("embeddings".to_string(), embeddings);
+    for (i, path) in paths.iter().enumerate() {
+        let path_bytes = Tensor::from_vec(path.as_bytes().to_vec(), path.len(), &Device::Cpu)?;
+        tensors.insert(format!("path/{i}"), path_bytes);
+    }
+    candle::safetensors::save(&tensors, index_out)?;
+    println!("wrote {} image embeddings to {index_out}", paths.len());
+    Ok(())
+}
+
+/// Loads an index written by [`build_index`] and prints the `top_k` images
+/// whose embedding has the highest cosine similarity with `query`.
+fn search_index(
+    model: &siglip::Model,
+    config: &siglip::Config,
+    tokenizer: &Tokenizer,
+    index: &str,
+    query: &str,
+    top_k: usize,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let tensors = candle::safetensors::load(index, device)?;
+    let embeddings = tensors
+        .get("embeddings")
+        .ok_or_else(|| anyhow::anyhow!("{index} has no `embeddings` tensor"))?;
+    let mut paths = vec![];
+    loop {
+        let Some(path_bytes) = tensors.get(&format!("path/{}", paths.len())) else {
+            break;
+        };
+        let path_bytes = path_bytes.to_dtype(DType::U8)?.to_vec1::<u8>()?;
+        paths.push(String::from_utf8(path_bytes)?);
+    }
+    let (input_ids, _) =
+        tokenize_sequences(config, Some(vec![query.to_string()]), tokenizer, device)?;
+    let text_embeds = model.text_features(&input_ids)?;
+    // Every row is already L2-normalized, so the matmul directly yields
+    // cosine similarities.
+    let scores = embeddings.matmul(&text_embeds.t()?)?.flatten_all()?.to_vec1::<f32>()?;
+    let mut ranked: Vec<(usize, f32)> = scores.into_iter().enumerate().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    println!("top {top_k} results for \"{query}\":");
+    for (rank, (idx, score)) in ranked.into_iter().take(top_k).enumerate() {
+        println!("{:>2}. {:.4}  {}", rank + 1, score, paths[idx]);
+    }
+    Ok(())
+}
+
 pub fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let hf_repo = match args.hf_repo.as_ref() {
@@ -106,13 +382,14 @@ pub fn main() -> anyhow::Result<()> {
             Which::V2LargePatch16_256 => "google/siglip2-large-patch16-256",
             Which::V2LargePatch16_384 => "google/siglip2-large-patch16-384",
             Which::V2LargePatch16_512 => "google/siglip2-large-patch16-512",
+            Which::V2BasePatch16Naflex => "google/siglip2-base-patch16-naflex",
         },
     };
     let model_file = match args.model {
         None => {
             let api = hf_hub::api::sync::Api::new()?;
             let api = api.model(hf_repo.to_string());
-            api.get("model.safetensors")?
+            api.get(if args.gguf { "model.gguf" } else { "model.safetensors" })?
         }
         Some(model) => model.into(),
     };
@@ -125,8 +402,38 @@ pub fn main() -> anyhow::Result<()> {
         Some(config) => config.into(),
     };
     let tokenizer = get_tokenizer(hf_repo, args.tokenizer)?;
-    let config: siglip::Config = serde_json::from_slice(&std::fs::read(config_file)?)?;
+    let mut config: siglip::Config = serde_json::from_slice(&std::fs::read(config_file)?)?;
+    config.vision_config.naflex = args.which.is_naflex();
     let device = candle_examples::device(args.cpu)?;
+    let dtype = DType::from(args.dtype);
+
+    if args.build_index.is_some() || args.index.is_some() {
+        if args.gguf || args.which.is_naflex() {
+            anyhow::bail!("--build-index/--index do not support --gguf or NaFlex checkpoints yet");
+        }
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[model_file.clone()], dtype, &device)? };
+        let model = siglip::Model::new(&config, vb)?;
+        if let Some(dir) = args.build_index.as_ref() {
+            build_index(
+                &model,
+                dir,
+                args.image_size.unwrap_or(config.vision_config.image_size),
+                dtype,
+                &device,
+                &args.index_out,
+            )?;
+        }
+        if let Some(index) = args.index.as_ref() {
+            let query = args
+                .query
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("--index requires --query"))?;
+            search_index(&model, &config, &tokenizer, index, query, args.top_k, &device)?;
+        }
+        return Ok(());
+    }
+
     let vec_imgs = match args.images {
         Some(imgs) => imgs,
         None => vec![
@@ -134,16 +441,46 @@ pub fn main() -> anyhow::Result<()> {
             "candle-examples/examples/yolo-v8/assets/bike.jpg".to_string(),
         ],
     };
-    let images = load_images(
-        &vec_imgs,
-        args.image_size.unwrap_or(config.vision_config.image_size),
-    )?
-    .to_device(&device)?;
-    let vb =
-        unsafe { VarBuilder::from_mmaped_safetensors(&[model_file.clone()], DType::F32, &device)? };
-    let model = siglip::Model::new(&config, vb)?;
     let (input_ids, vec_seq) = tokenize_sequences(&config, args.sequences, &tokenizer, &device)?;
-    let (_logits_per_text, logits_per_image) = model.forward(&images, &input_ids)?;
+    let logits_per_image = if args.gguf {
+        if args.which.is_naflex() {
+            anyhow::bail!("the --gguf path does not support NaFlex checkpoints yet");
+        }
+        let vb = QuantizedVarBuilder::from_gguf(&model_file, &device)?;
+        let model = quantized_siglip::Model::new(&config, vb)?;
+        let images = load_images(
+            &vec_imgs,
+            args.image_size.unwrap_or(config.vision_config.image_size),
+            DType::F32,
+        )?
+        .to_device(&device)?;
+        let (_logits_per_text, logits_per_image) = model.forward(&images, &input_ids)?;
+        logits_per_image
+    } else {
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[model_file.clone()], dtype, &device)? };
+        let model = siglip::Model::new(&config, vb)?;
+        let (_logits_per_text, logits_per_image) = if args.which.is_naflex() {
+            let (patches, mask, spatial_shapes) = load_images_naflex(
+                &vec_imgs,
+                config.vision_config.patch_size,
+                args.max_num_patches,
+                dtype,
+            )?;
+            let patches = patches.to_device(&device)?;
+            let mask = mask.to_device(&device)?;
+            model.forward_naflex(&patches, &mask, &spatial_shapes, &input_ids)?
+        } else {
+            let images = load_images(
+                &vec_imgs,
+                args.image_size.unwrap_or(config.vision_config.image_size),
+                dtype,
+            )?
+            .to_device(&device)?;
+            model.forward(&images, &input_ids)?
+        };
+        logits_per_image
+    };
     let softmax_image = softmax(&logits_per_image, 1)?;
     let softmax_image_vec = softmax_image.flatten_all()?.to_vec1::<f32>()?;
     println!("softmax_image_vec: {softmax_image_vec:?}");
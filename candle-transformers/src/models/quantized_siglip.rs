@@ -0,0 +1,378 @@
+//! Quantized SigLIP / SigLIP2, loaded from a GGUF file.
+//!
+//! Mirrors [`crate::models::siglip`], but builds its linear layers and
+//! embedding tables from a [`candle::quantized::gguf_file::Content`] via
+//! [`crate::quantized_var_builder::VarBuilder`] instead of a safetensors
+//! checkpoint, so that large checkpoints (e.g. `v2-large-patch16-512`) can be
+//! run q4_0/q4_k/q8_0-quantized to fit on a laptop. Weight matrices stay
+//! quantized in memory and are dequantized on the fly for each matmul.
+
+use crate::quantized_nn::{layer_norm, linear, Embedding, LayerNorm, Linear};
+use crate::quantized_var_builder::VarBuilder;
+use crate::models::siglip::{Config, TextConfig, VisionConfig};
+use candle::{IndexOp, Module, Result, Tensor, D};
+use candle_nn::Conv2d;
+
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+    scale: f64,
+}
+
+impl Attention {
+    fn new(hidden_size: usize, num_attention_heads: usize, vb: VarBuilder) -> Result<Self> {
+        let q_proj = linear(hidden_size, hidden_size, vb.pp("q_proj"))?;
+        let k_proj = linear(hidden_size, hidden_size, vb.pp("k_proj"))?;
+        let v_proj = linear(hidden_size, hidden_size, vb.pp("v_proj"))?;
+        let out_proj = linear(hidden_size, hidden_size, vb.pp("out_proj"))?;
+        let head_dim = hidden_size / num_attention_heads;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            out_proj,
+            num_heads: num_attention_heads,
+            head_dim,
+            scale: (head_dim as f64).powf(-0.5),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_cross(xs, xs)
+    }
+
+    fn forward_cross(&self, xs_q: &Tensor, xs_kv: &Tensor) -> Result<Tensor> {
+        let (bsz, q_len, _) = xs_q.dims3()?;
+        let (_, kv_len, _) = xs_kv.dims3()?;
+        let q = self.q_proj.forward(xs_q)?;
+        let k = self.k_proj.forward(xs_kv)?;
+        let v = self.v_proj.forward(xs_kv)?;
+        let q = q
+            .reshape((bsz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((bsz, kv_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = v
+            .reshape((bsz, kv_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let attn_weights = (q.matmul(&k.transpose(D::Minus1, D::Minus2)?)? * self.scale)?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_weights.matmul(&v)?;
+        let attn_output = attn_output
+            .transpose(1, 2)?
+            .reshape((bsz, q_len, self.num_heads * self.head_dim))?;
+        self.out_proj.forward(&attn_output)
+    }
+}
+
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Mlp {
+    fn new(hidden_size: usize, intermediate_size: usize, vb: VarBuilder) -> Result<Self> {
+        let fc1 = linear(hidden_size, intermediate_size, vb.pp("fc1"))?;
+        let fc2 = linear(intermediate_size, hidden_size, vb.pp("fc2"))?;
+        Ok(Self { fc1, fc2 })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // SigLIP's `gelu_pytorch_tanh` activation.
+        xs.apply(&self.fc1)?.gelu()?.apply(&self.fc2)
+    }
+}
+
+struct EncoderLayer {
+    self_attn: Attention,
+    layer_norm1: LayerNorm,
+    mlp: Mlp,
+    layer_norm2: LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(
+        hidden_size: usize,
+        intermediate_size: usize,
+        num_attention_heads: usize,
+        layer_norm_eps: f64,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(hidden_size, num_attention_heads, vb.pp("self_attn"))?,
+            layer_norm1: layer_norm(hidden_size, layer_norm_eps, vb.pp("layer_norm1"))?,
+            mlp: Mlp::new(hidden_size, intermediate_size, vb.pp("mlp"))?,
+            layer_norm2: layer_norm(hidden_size, layer_norm_eps, vb.pp("layer_norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.layer_norm1.forward(xs)?;
+        let xs = self.self_attn.forward(&xs)?;
+        let xs = (residual + xs)?;
+        let residual = &xs;
+        let xs = self.layer_norm2.forward(&xs)?;
+        let xs = self.mlp.forward(&xs)?;
+        residual + xs
+    }
+}
+
+struct Encoder {
+    layers: Vec<EncoderLayer>,
+}
+
+impl Encoder {
+    fn new(
+        num_hidden_layers: usize,
+        hidden_size: usize,
+        intermediate_size: usize,
+        num_attention_heads: usize,
+        layer_norm_eps: f64,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let vb = vb.pp("layers");
+        let mut layers = Vec::with_capacity(num_hidden_layers);
+        for i in 0..num_hidden_layers {
+            layers.push(EncoderLayer::new(
+                hidden_size,
+                intermediate_size,
+                num_attention_heads,
+                layer_norm_eps,
+                vb.pp(i),
+            )?);
+        }
+        Ok(Self { layers })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?;
+        }
+        Ok(xs)
+    }
+}
+
+struct VisionEmbeddings {
+    patch_embedding: Conv2d,
+    position_embedding: Embedding,
+    num_patches_per_side: usize,
+}
+
+impl VisionEmbeddings {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let conv_vb = vb.pp("patch_embedding");
+        let weight = conv_vb
+            .get(
+                (cfg.hidden_size, cfg.num_channels, cfg.patch_size, cfg.patch_size),
+                "weight",
+            )?
+            .dequantize(conv_vb.device())?;
+        let bias = conv_vb
+            .get(cfg.hidden_size, "bias")?
+            .dequantize(conv_vb.device())?;
+        let conv_cfg = candle_nn::Conv2dConfig {
+            stride: cfg.patch_size,
+            ..Default::default()
+        };
+        let patch_embedding = Conv2d::new(weight, Some(bias), conv_cfg);
+        let num_patches_per_side = cfg.image_size / cfg.patch_size;
+        let position_embedding = crate::quantized_nn::embedding(
+            num_patches_per_side * num_patches_per_side,
+            cfg.hidden_size,
+            vb.pp("position_embedding"),
+        )?;
+        Ok(Self {
+            patch_embedding,
+            position_embedding,
+            num_patches_per_side,
+        })
+    }
+
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let patch_embeds = self.patch_embedding.forward(pixel_values)?;
+        let embeddings = patch_embeds.flatten_from(2)?.transpose(1, 2)?;
+        let position_ids = Tensor::arange(
+            0u32,
+            (self.num_patches_per_side * self.num_patches_per_side) as u32,
+            embeddings.device(),
+        )?;
+        let position_embedding = self.position_embedding.forward(&position_ids)?.unsqueeze(0)?;
+        embeddings.broadcast_add(&position_embedding)
+    }
+}
+
+struct MultiheadAttentionPoolingHead {
+    probe: Tensor,
+    attention: Attention,
+    layernorm: LayerNorm,
+    mlp: Mlp,
+}
+
+impl MultiheadAttentionPoolingHead {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let probe = vb
+            .get((1, 1, cfg.hidden_size), "probe")?
+            .dequantize(vb.device())?;
+        Ok(Self {
+            probe,
+            attention: Attention::new(cfg.hidden_size, cfg.num_attention_heads, vb.pp("attention"))?,
+            layernorm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layernorm"))?,
+            mlp: Mlp::new(cfg.hidden_size, cfg.intermediate_size, vb.pp("mlp"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let batch_size = xs.dim(0)?;
+        let probe = self.probe.repeat((batch_size, 1, 1))?;
+        let hidden_state = self.attention.forward_cross(&probe, xs)?;
+        let hidden_state = hidden_state.i((.., 0, ..))?;
+        let residual = &hidden_state;
+        let hidden_state = self.layernorm.forward(&hidden_state)?;
+        residual + self.mlp.forward(&hidden_state)?
+    }
+}
+
+pub struct VisionModel {
+    embeddings: VisionEmbeddings,
+    encoder: Encoder,
+    post_layernorm: LayerNorm,
+    head: MultiheadAttentionPoolingHead,
+}
+
+impl VisionModel {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            embeddings: VisionEmbeddings::new(cfg, vb.pp("embeddings"))?,
+            encoder: Encoder::new(
+                cfg.num_hidden_layers,
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                cfg.num_attention_heads,
+                cfg.layer_norm_eps,
+                vb.pp("encoder"),
+            )?,
+            post_layernorm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("post_layernorm"))?,
+            head: MultiheadAttentionPoolingHead::new(cfg, vb.pp("head"))?,
+        })
+    }
+
+    pub fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.embeddings.forward(pixel_values)?;
+        let encoder_outputs = self.encoder.forward(&hidden_states)?;
+        let last_hidden_state = self.post_layernorm.forward(&encoder_outputs)?;
+        self.head.forward(&last_hidden_state)
+    }
+}
+
+struct TextEmbeddings {
+    token_embedding: Embedding,
+    position_embedding: Embedding,
+}
+
+impl TextEmbeddings {
+    fn new(cfg: &TextConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            token_embedding: crate::quantized_nn::embedding(
+                cfg.vocab_size,
+                cfg.hidden_size,
+                vb.pp("token_embedding"),
+            )?,
+            position_embedding: crate::quantized_nn::embedding(
+                cfg.max_position_embeddings,
+                cfg.hidden_size,
+                vb.pp("position_embedding"),
+            )?,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let inputs_embeds = self.token_embedding.forward(input_ids)?;
+        let position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+        let position_embedding = self.position_embedding.forward(&position_ids)?.unsqueeze(0)?;
+        inputs_embeds.broadcast_add(&position_embedding)
+    }
+}
+
+pub struct TextModel {
+    embeddings: TextEmbeddings,
+    encoder: Encoder,
+    final_layer_norm: LayerNorm,
+    head: Linear,
+}
+
+impl TextModel {
+    fn new(cfg: &TextConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            embeddings: TextEmbeddings::new(cfg, vb.pp("embeddings"))?,
+            encoder: Encoder::new(
+                cfg.num_hidden_layers,
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                cfg.num_attention_heads,
+                cfg.layer_norm_eps,
+                vb.pp("encoder"),
+            )?,
+            final_layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("final_layer_norm"))?,
+            head: linear(cfg.hidden_size, cfg.hidden_size, vb.pp("head"))?,
+        })
+    }
+
+    pub fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.embeddings.forward(input_ids)?;
+        let encoder_outputs = self.encoder.forward(&hidden_states)?;
+        let last_hidden_state = self.final_layer_norm.forward(&encoder_outputs)?;
+        let pooled_output = last_hidden_state.i((.., 0, ..))?;
+        self.head.forward(&pooled_output)
+    }
+}
+
+pub struct Model {
+    text_model: TextModel,
+    vision_model: VisionModel,
+    logit_scale: Tensor,
+    logit_bias: Tensor,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            text_model: TextModel::new(&cfg.text_config, vb.pp("text_model"))?,
+            vision_model: VisionModel::new(&cfg.vision_config, vb.pp("vision_model"))?,
+            logit_scale: vb.get(1, "logit_scale")?.dequantize(vb.device())?,
+            logit_bias: vb.get(1, "logit_bias")?.dequantize(vb.device())?,
+        })
+    }
+
+    pub fn get_text_features(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.text_model.forward(input_ids)
+    }
+
+    pub fn get_image_features(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        self.vision_model.forward(pixel_values)
+    }
+
+    pub fn forward(&self, pixel_values: &Tensor, input_ids: &Tensor) -> Result<(Tensor, Tensor)> {
+        let image_embeds = crate::models::siglip::div_l2_norm(&self.get_image_features(pixel_values)?)?;
+        let text_embeds = crate::models::siglip::div_l2_norm(&self.get_text_features(input_ids)?)?;
+        let logits_per_text = text_embeds.matmul(&image_embeds.t()?)?;
+        let logit_scale = self.logit_scale.exp()?;
+        let logits_per_text = logits_per_text
+            .broadcast_mul(&logit_scale)?
+            .broadcast_add(&self.logit_bias)?;
+        let logits_per_image = logits_per_text.t()?;
+        Ok((logits_per_text, logits_per_image))
+    }
+}
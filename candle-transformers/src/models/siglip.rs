@@ -0,0 +1,775 @@
+//! SigLIP and SigLIP2 implementation.
+//!
+//! Contrastive image-text pretraining with sigmoid loss, see:
+//! - ["Sigmoid Loss for Language Image Pre-Training"](https://arxiv.org/abs/2303.15343)
+//! - ["SigLIP 2: Multilingual Vision-Language Encoders with Improved Semantic
+//!   Understanding, Localization, and Dense Features"](https://arxiv.org/abs/2502.14786)
+
+use candle::{DType, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{layer_norm, linear, Conv2d, Conv2dConfig, LayerNorm, Linear, VarBuilder};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Activation {
+    #[serde(rename = "gelu_pytorch_tanh")]
+    GeluPytorchTanh,
+}
+
+impl Module for Activation {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Activation::GeluPytorchTanh => xs.gelu(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextConfig {
+    pub vocab_size: usize,
+    #[serde(default = "default_hidden_size")]
+    pub hidden_size: usize,
+    #[serde(default = "default_intermediate_size")]
+    pub intermediate_size: usize,
+    #[serde(default = "default_num_hidden_layers")]
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_num_attention_heads")]
+    pub num_attention_heads: usize,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+    #[serde(default = "default_pad_token_id")]
+    pub pad_token_id: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VisionConfig {
+    #[serde(default = "default_hidden_size")]
+    pub hidden_size: usize,
+    #[serde(default = "default_intermediate_size")]
+    pub intermediate_size: usize,
+    #[serde(default = "default_num_hidden_layers")]
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_num_attention_heads")]
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    pub image_size: usize,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+    /// Set for the SigLIP2 NaFlex checkpoints, whose patch embedding is a
+    /// linear projection of flattened patches rather than a strided conv2d,
+    /// so that variable aspect ratios can be fed in without resizing/cropping.
+    #[serde(default)]
+    pub naflex: bool,
+}
+
+fn default_hidden_size() -> usize {
+    768
+}
+fn default_intermediate_size() -> usize {
+    3072
+}
+fn default_num_hidden_layers() -> usize {
+    12
+}
+fn default_num_attention_heads() -> usize {
+    12
+}
+fn default_max_position_embeddings() -> usize {
+    64
+}
+fn default_hidden_act() -> Activation {
+    Activation::GeluPytorchTanh
+}
+fn default_layer_norm_eps() -> f64 {
+    1e-6
+}
+fn default_pad_token_id() -> u32 {
+    1
+}
+fn default_num_channels() -> usize {
+    3
+}
+fn default_patch_size() -> usize {
+    16
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub text_config: TextConfig,
+    pub vision_config: VisionConfig,
+}
+
+impl Config {
+    // Convenience constructor for the `v1-base-patch16-224` config.
+    pub fn base_patch16_224() -> Self {
+        Self {
+            text_config: TextConfig {
+                vocab_size: 32000,
+                hidden_size: 768,
+                intermediate_size: 3072,
+                num_hidden_layers: 12,
+                num_attention_heads: 12,
+                max_position_embeddings: 64,
+                hidden_act: Activation::GeluPytorchTanh,
+                layer_norm_eps: 1e-6,
+                pad_token_id: 1,
+            },
+            vision_config: VisionConfig {
+                hidden_size: 768,
+                intermediate_size: 3072,
+                num_hidden_layers: 12,
+                num_attention_heads: 12,
+                num_channels: 3,
+                image_size: 224,
+                patch_size: 16,
+                hidden_act: Activation::GeluPytorchTanh,
+                layer_norm_eps: 1e-6,
+                naflex: false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+    scale: f64,
+}
+
+impl Attention {
+    fn new(hidden_size: usize, num_attention_heads: usize, vb: VarBuilder) -> Result<Self> {
+        let q_proj = linear(hidden_size, hidden_size, vb.pp("q_proj"))?;
+        let k_proj = linear(hidden_size, hidden_size, vb.pp("k_proj"))?;
+        let v_proj = linear(hidden_size, hidden_size, vb.pp("v_proj"))?;
+        let out_proj = linear(hidden_size, hidden_size, vb.pp("out_proj"))?;
+        let head_dim = hidden_size / num_attention_heads;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            out_proj,
+            num_heads: num_attention_heads,
+            head_dim,
+            scale: (head_dim as f64).powf(-0.5),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        self.forward_cross(xs, xs, attention_mask)
+    }
+
+    // `attention_mask` is an additive mask, already broadcastable to
+    // `(bsz, 1, q_len, kv_len)`, with `0.` on valid positions and a large
+    // negative value on masked ones. `xs_q` and `xs_kv` are the same tensor
+    // for plain self-attention, and differ for the pooling head's
+    // probe-as-query cross-attention.
+    fn forward_cross(&self, xs_q: &Tensor, xs_kv: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let (bsz, q_len, _) = xs_q.dims3()?;
+        let (_, kv_len, _) = xs_kv.dims3()?;
+        let q = self.q_proj.forward(xs_q)?;
+        let k = self.k_proj.forward(xs_kv)?;
+        let v = self.v_proj.forward(xs_kv)?;
+        let q = q
+            .reshape((bsz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((bsz, kv_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = v
+            .reshape((bsz, kv_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let attn_weights = (q.matmul(&k.transpose(D::Minus1, D::Minus2)?)? * self.scale)?;
+        let attn_weights = match attention_mask {
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+            None => attn_weights,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        let attn_output = attn_weights.matmul(&v)?;
+        let attn_output = attn_output
+            .transpose(1, 2)?
+            .reshape((bsz, q_len, self.num_heads * self.head_dim))?;
+        self.out_proj.forward(&attn_output)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+    activation: Activation,
+}
+
+impl Mlp {
+    fn new(hidden_size: usize, intermediate_size: usize, activation: Activation, vb: VarBuilder) -> Result<Self> {
+        let fc1 = linear(hidden_size, intermediate_size, vb.pp("fc1"))?;
+        let fc2 = linear(intermediate_size, hidden_size, vb.pp("fc2"))?;
+        Ok(Self { fc1, fc2, activation })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.fc1)?.apply(&self.activation)?.apply(&self.fc2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EncoderLayer {
+    self_attn: Attention,
+    layer_norm1: LayerNorm,
+    mlp: Mlp,
+    layer_norm2: LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(
+        hidden_size: usize,
+        intermediate_size: usize,
+        num_attention_heads: usize,
+        hidden_act: Activation,
+        layer_norm_eps: f64,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(hidden_size, num_attention_heads, vb.pp("self_attn"))?;
+        let layer_norm1 = layer_norm(hidden_size, layer_norm_eps, vb.pp("layer_norm1"))?;
+        let mlp = Mlp::new(hidden_size, intermediate_size, hidden_act, vb.pp("mlp"))?;
+        let layer_norm2 = layer_norm(hidden_size, layer_norm_eps, vb.pp("layer_norm2"))?;
+        Ok(Self {
+            self_attn,
+            layer_norm1,
+            mlp,
+            layer_norm2,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.layer_norm1.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask)?;
+        let xs = (residual + xs)?;
+        let residual = &xs;
+        let xs = self.layer_norm2.forward(&xs)?;
+        let xs = self.mlp.forward(&xs)?;
+        residual + xs
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Encoder {
+    layers: Vec<EncoderLayer>,
+}
+
+impl Encoder {
+    fn new(
+        num_hidden_layers: usize,
+        hidden_size: usize,
+        intermediate_size: usize,
+        num_attention_heads: usize,
+        hidden_act: Activation,
+        layer_norm_eps: f64,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let vb = vb.pp("layers");
+        let mut layers = Vec::with_capacity(num_hidden_layers);
+        for i in 0..num_hidden_layers {
+            let layer = EncoderLayer::new(
+                hidden_size,
+                intermediate_size,
+                num_attention_heads,
+                hidden_act,
+                layer_norm_eps,
+                vb.pp(i),
+            )?;
+            layers.push(layer);
+        }
+        Ok(Self { layers })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, attention_mask)?;
+        }
+        Ok(xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PatchEmbedding {
+    // Fixed-resolution checkpoints: a strided conv2d over the raw image.
+    Conv2d(Conv2d),
+    // NaFlex checkpoints: a linear projection of pre-flattened PxPxC patches,
+    // since images keep their native aspect ratio and are never resized to a
+    // square grid.
+    Linear(Linear),
+}
+
+#[derive(Debug, Clone)]
+struct VisionEmbeddings {
+    patch_embedding: PatchEmbedding,
+    position_embedding: candle_nn::Embedding,
+    patch_size: usize,
+    num_patches_per_side: usize,
+}
+
+impl VisionEmbeddings {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let patch_embedding = if cfg.naflex {
+            let patch_dim = cfg.num_channels * cfg.patch_size * cfg.patch_size;
+            PatchEmbedding::Linear(linear(patch_dim, cfg.hidden_size, vb.pp("patch_embedding"))?)
+        } else {
+            let conv_cfg = Conv2dConfig {
+                stride: cfg.patch_size,
+                ..Default::default()
+            };
+            PatchEmbedding::Conv2d(candle_nn::conv2d(
+                cfg.num_channels,
+                cfg.hidden_size,
+                cfg.patch_size,
+                conv_cfg,
+                vb.pp("patch_embedding"),
+            )?)
+        };
+        let num_patches_per_side = cfg.image_size / cfg.patch_size;
+        let position_embedding = candle_nn::embedding(
+            num_patches_per_side * num_patches_per_side,
+            cfg.hidden_size,
+            vb.pp("position_embedding"),
+        )?;
+        Ok(Self {
+            patch_embedding,
+            position_embedding,
+            patch_size: cfg.patch_size,
+            num_patches_per_side,
+        })
+    }
+
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let conv = match &self.patch_embedding {
+            PatchEmbedding::Conv2d(conv) => conv,
+            PatchEmbedding::Linear(_) => {
+                candle::bail!("VisionEmbeddings::forward called on a NaFlex checkpoint, use forward_naflex")
+            }
+        };
+        let patch_embeds = conv.forward(pixel_values)?;
+        let embeddings = patch_embeds.flatten_from(2)?.transpose(1, 2)?;
+        let position_ids = Tensor::arange(
+            0u32,
+            (self.num_patches_per_side * self.num_patches_per_side) as u32,
+            embeddings.device(),
+        )?;
+        let position_embedding = self.position_embedding.forward(&position_ids)?.unsqueeze(0)?;
+        embeddings.broadcast_add(&position_embedding)
+    }
+
+    /// NaFlex forward pass: `patches` holds row-major, PxP-flattened patches
+    /// for every image in the batch, zero-padded up to a common sequence
+    /// length, with shape `(bsz, max_num_patches, patch_size^2 * channels)`.
+    /// `spatial_shapes` gives the valid `(rows, cols)` patch grid for each
+    /// image so the learned position embeddings can be resampled to it.
+    fn forward_naflex(&self, patches: &Tensor, spatial_shapes: &[(usize, usize)]) -> Result<Tensor> {
+        let linear = match &self.patch_embedding {
+            PatchEmbedding::Linear(linear) => linear,
+            PatchEmbedding::Conv2d(_) => {
+                candle::bail!("VisionEmbeddings::forward_naflex called on a fixed-resolution checkpoint")
+            }
+        };
+        let embeddings = linear.forward(patches)?;
+        let (bsz, max_num_patches, hidden_size) = embeddings.dims3()?;
+        let device = embeddings.device();
+        let dtype = embeddings.dtype();
+        let mut pos_embeds = Vec::with_capacity(bsz);
+        for &(rows, cols) in spatial_shapes {
+            let resampled = self.resample_position_embedding(rows, cols, device, dtype)?;
+            let valid = rows * cols;
+            let padded = if valid < max_num_patches {
+                resampled.pad_with_zeros(0, 0, max_num_patches - valid)?
+            } else {
+                resampled
+            };
+            pos_embeds.push(padded.reshape((1, max_num_patches, hidden_size))?);
+        }
+        let pos_embeds = Tensor::cat(&pos_embeds, 0)?;
+        embeddings + pos_embeds
+    }
+
+    /// Bilinearly interpolates the learned `num_patches_per_side x
+    /// num_patches_per_side` position embedding grid to an arbitrary `rows x
+    /// cols` grid, matching the aspect ratio of the resized input image.
+    fn resample_position_embedding(
+        &self,
+        rows: usize,
+        cols: usize,
+        device: &candle::Device,
+        dtype: candle::DType,
+    ) -> Result<Tensor> {
+        let side = self.num_patches_per_side;
+        if rows == side && cols == side {
+            let position_ids = Tensor::arange(0u32, (side * side) as u32, device)?;
+            return self.position_embedding.forward(&position_ids)?.to_dtype(dtype);
+        }
+        let hidden_size = self.position_embedding.embeddings().dim(1)?;
+        let weight = self
+            .position_embedding
+            .embeddings()
+            .to_dtype(candle::DType::F32)?
+            .to_vec2::<f32>()?;
+        // `align_corners=False`, antialiased (triangle-filter) resampling, matching
+        // `Siglip2VisionEmbeddings.resize_positional_embeddings` in the HF reference:
+        // the filter support widens on downscale so each output patch averages over
+        // the input patches it actually covers, instead of aliasing a single sample.
+        let row_weights = resample_weights(rows, side);
+        let col_weights = resample_weights(cols, side);
+        // Resample rows first (side x side -> rows x side), then columns
+        // (rows x side -> rows x cols); bilinear resampling is separable.
+        let mut rows_resampled = vec![0f32; rows * side * hidden_size];
+        for (r, weights) in row_weights.iter().enumerate() {
+            for x in 0..side {
+                let dst = &mut rows_resampled[(r * side + x) * hidden_size..(r * side + x + 1) * hidden_size];
+                for &(y, w) in weights {
+                    let src = &weight[y * side + x];
+                    for h in 0..hidden_size {
+                        dst[h] += src[h] * w;
+                    }
+                }
+            }
+        }
+        let mut out = vec![0f32; rows * cols * hidden_size];
+        for r in 0..rows {
+            for (c, weights) in col_weights.iter().enumerate() {
+                let dst = &mut out[(r * cols + c) * hidden_size..(r * cols + c + 1) * hidden_size];
+                for &(x, w) in weights {
+                    let src = &rows_resampled[(r * side + x) * hidden_size..(r * side + x + 1) * hidden_size];
+                    for h in 0..hidden_size {
+                        dst[h] += src[h] * w;
+                    }
+                }
+            }
+        }
+        Tensor::from_vec(out, (rows * cols, hidden_size), device)?.to_dtype(dtype)
+    }
+}
+
+/// Per-output-index `(input_index, weight)` lists for a 1-D `align_corners=False`
+/// triangle-filter resize from `in_size` to `out_size` samples. On upscale the
+/// filter support is a single input pixel either side (plain bilinear); on
+/// downscale the support widens to the scale factor so the filter averages over
+/// every input sample an output sample covers (antialiasing).
+fn resample_weights(out_size: usize, in_size: usize) -> Vec<Vec<(usize, f32)>> {
+    if out_size == in_size {
+        return (0..out_size).map(|i| vec![(i, 1.0)]).collect();
+    }
+    let scale = in_size as f32 / out_size as f32;
+    let support = scale.max(1.0);
+    (0..out_size)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale - 0.5;
+            let lo = (center - support).floor().max(0.0) as isize;
+            let hi = ((center + support).ceil() as isize).min(in_size as isize - 1);
+            let mut weights = Vec::new();
+            let mut total = 0f32;
+            for j in lo..=hi {
+                let j = j as usize;
+                let w = (1.0 - (center - j as f32).abs() / support).max(0.0);
+                if w > 0.0 {
+                    weights.push((j, w));
+                    total += w;
+                }
+            }
+            if total > 0.0 {
+                for w in weights.iter_mut() {
+                    w.1 /= total;
+                }
+            }
+            weights
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct VisionTransformer {
+    embeddings: VisionEmbeddings,
+    encoder: Encoder,
+    post_layernorm: LayerNorm,
+    head: MultiheadAttentionPoolingHead,
+}
+
+impl VisionTransformer {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let embeddings = VisionEmbeddings::new(cfg, vb.pp("embeddings"))?;
+        let encoder = Encoder::new(
+            cfg.num_hidden_layers,
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            cfg.num_attention_heads,
+            cfg.hidden_act,
+            cfg.layer_norm_eps,
+            vb.pp("encoder"),
+        )?;
+        let post_layernorm = layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("post_layernorm"))?;
+        let head = MultiheadAttentionPoolingHead::new(cfg, vb.pp("head"))?;
+        Ok(Self {
+            embeddings,
+            encoder,
+            post_layernorm,
+            head,
+        })
+    }
+
+    pub fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.embeddings.forward(pixel_values)?;
+        let encoder_outputs = self.encoder.forward(&hidden_states, None)?;
+        let last_hidden_state = self.post_layernorm.forward(&encoder_outputs)?;
+        self.head.forward(&last_hidden_state, None)
+    }
+
+    /// NaFlex forward pass for variable-aspect-ratio images, see
+    /// [`VisionEmbeddings::forward_naflex`]. `pixel_attention_mask` is a
+    /// `(bsz, max_num_patches)` boolean mask (1 = real patch, 0 = padding),
+    /// and `spatial_shapes` gives each image's `(rows, cols)` patch grid.
+    pub fn forward_naflex(
+        &self,
+        pixel_values: &Tensor,
+        pixel_attention_mask: &Tensor,
+        spatial_shapes: &[(usize, usize)],
+    ) -> Result<Tensor> {
+        let hidden_states = self.embeddings.forward_naflex(pixel_values, spatial_shapes)?;
+        let attention_mask = additive_attention_mask(pixel_attention_mask, hidden_states.dtype())?;
+        let encoder_outputs = self.encoder.forward(&hidden_states, Some(&attention_mask))?;
+        let last_hidden_state = self.post_layernorm.forward(&encoder_outputs)?;
+        // The pooling head's probe is the query, not a key, so the mask over
+        // `last_hidden_state` (the keys) stays `max_num_patches` wide.
+        self.head.forward(&last_hidden_state, Some(&attention_mask))
+    }
+}
+
+/// Turns a `(bsz, kv_len)` boolean (1/0) padding mask into an additive mask
+/// broadcastable to `(bsz, num_heads, q_len, kv_len)`: `0.` where attention is
+/// allowed, a large negative value where the key is padding.
+fn additive_attention_mask(mask: &Tensor, dtype: candle::DType) -> Result<Tensor> {
+    let mask = mask.to_dtype(candle::DType::F32)?;
+    let inverted = (1.0 - mask)?;
+    let additive = (inverted * f32::MIN as f64)?;
+    additive.to_dtype(dtype)?.unsqueeze(1)?.unsqueeze(1)
+}
+
+#[derive(Debug, Clone)]
+struct MultiheadAttentionPoolingHead {
+    probe: Tensor,
+    attention: Attention,
+    layernorm: LayerNorm,
+    mlp: Mlp,
+}
+
+impl MultiheadAttentionPoolingHead {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let probe = vb.get((1, 1, cfg.hidden_size), "probe")?;
+        let attention = Attention::new(cfg.hidden_size, cfg.num_attention_heads, vb.pp("attention"))?;
+        let layernorm = layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layernorm"))?;
+        let mlp = Mlp::new(cfg.hidden_size, cfg.intermediate_size, cfg.hidden_act, vb.pp("mlp"))?;
+        Ok(Self {
+            probe,
+            attention,
+            layernorm,
+            mlp,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let batch_size = xs.dim(0)?;
+        let probe = self.probe.repeat((batch_size, 1, 1))?;
+        let hidden_state = self.attention.forward_cross(&probe, xs, attention_mask)?;
+        let hidden_state = hidden_state.i((.., 0, ..))?;
+        let residual = &hidden_state;
+        let hidden_state = self.layernorm.forward(&hidden_state)?;
+        let hidden_state = (residual + self.mlp.forward(&hidden_state)?)?;
+        Ok(hidden_state)
+    }
+}
+
+pub type VisionModel = VisionTransformer;
+
+#[derive(Debug, Clone)]
+struct TextEmbeddings {
+    token_embedding: candle_nn::Embedding,
+    position_embedding: candle_nn::Embedding,
+}
+
+impl TextEmbeddings {
+    fn new(cfg: &TextConfig, vb: VarBuilder) -> Result<Self> {
+        let token_embedding = candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("token_embedding"))?;
+        let position_embedding = candle_nn::embedding(
+            cfg.max_position_embeddings,
+            cfg.hidden_size,
+            vb.pp("position_embedding"),
+        )?;
+        Ok(Self {
+            token_embedding,
+            position_embedding,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        let inputs_embeds = self.token_embedding.forward(input_ids)?;
+        let position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+        let position_embedding = self.position_embedding.forward(&position_ids)?.unsqueeze(0)?;
+        inputs_embeds.broadcast_add(&position_embedding)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextTransformer {
+    embeddings: TextEmbeddings,
+    encoder: Encoder,
+    final_layer_norm: LayerNorm,
+    head: Linear,
+}
+
+impl TextTransformer {
+    fn new(cfg: &TextConfig, vb: VarBuilder) -> Result<Self> {
+        let embeddings = TextEmbeddings::new(cfg, vb.pp("embeddings"))?;
+        let encoder = Encoder::new(
+            cfg.num_hidden_layers,
+            cfg.hidden_size,
+            cfg.intermediate_size,
+            cfg.num_attention_heads,
+            cfg.hidden_act,
+            cfg.layer_norm_eps,
+            vb.pp("encoder"),
+        )?;
+        let final_layer_norm = layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("final_layer_norm"))?;
+        let head = linear(cfg.hidden_size, cfg.hidden_size, vb.pp("head"))?;
+        Ok(Self {
+            embeddings,
+            encoder,
+            final_layer_norm,
+            head,
+        })
+    }
+
+    pub fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.embeddings.forward(input_ids)?;
+        let encoder_outputs = self.encoder.forward(&hidden_states, None)?;
+        let last_hidden_state = self.final_layer_norm.forward(&encoder_outputs)?;
+        let pooled_output = last_hidden_state.i((.., 0, ..))?;
+        self.head.forward(&pooled_output)
+    }
+}
+
+pub type TextModel = TextTransformer;
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    text_model: TextTransformer,
+    vision_model: VisionTransformer,
+    logit_scale: Tensor,
+    logit_bias: Tensor,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let text_model = TextTransformer::new(&cfg.text_config, vb.pp("text_model"))?;
+        let vision_model = VisionTransformer::new(&cfg.vision_config, vb.pp("vision_model"))?;
+        let logit_scale = vb.get(&[1], "logit_scale")?;
+        let logit_bias = vb.get(&[1], "logit_bias")?;
+        Ok(Self {
+            text_model,
+            vision_model,
+            logit_scale,
+            logit_bias,
+        })
+    }
+
+    pub fn get_text_features(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.text_model.forward(input_ids)
+    }
+
+    pub fn get_image_features(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        self.vision_model.forward(pixel_values)
+    }
+
+    /// L2-normalized pooled image embeddings, before the logit-scale/bias
+    /// projection, suitable for nearest-neighbour search or building a
+    /// reusable embedding index.
+    pub fn image_features(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        div_l2_norm(&self.get_image_features(pixel_values)?.to_dtype(DType::F32)?)
+    }
+
+    /// L2-normalized pooled text embeddings, the text-side counterpart of
+    /// [`Self::image_features`].
+    pub fn text_features(&self, input_ids: &Tensor) -> Result<Tensor> {
+        div_l2_norm(&self.get_text_features(input_ids)?.to_dtype(DType::F32)?)
+    }
+
+    /// NaFlex counterpart of [`Self::get_image_features`], see
+    /// [`VisionTransformer::forward_naflex`].
+    pub fn get_image_features_naflex(
+        &self,
+        pixel_values: &Tensor,
+        pixel_attention_mask: &Tensor,
+        spatial_shapes: &[(usize, usize)],
+    ) -> Result<Tensor> {
+        self.vision_model
+            .forward_naflex(pixel_values, pixel_attention_mask, spatial_shapes)
+    }
+
+    pub fn forward(&self, pixel_values: &Tensor, input_ids: &Tensor) -> Result<(Tensor, Tensor)> {
+        let image_embeds = self.get_image_features(pixel_values)?;
+        let text_embeds = self.get_text_features(input_ids)?;
+        self.logits_from_embeds(&image_embeds, &text_embeds)
+    }
+
+    /// NaFlex counterpart of [`Self::forward`], preserving each image's
+    /// native aspect ratio instead of resizing it to a square.
+    pub fn forward_naflex(
+        &self,
+        pixel_values: &Tensor,
+        pixel_attention_mask: &Tensor,
+        spatial_shapes: &[(usize, usize)],
+        input_ids: &Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        let image_embeds = self.get_image_features_naflex(pixel_values, pixel_attention_mask, spatial_shapes)?;
+        let text_embeds = self.get_text_features(input_ids)?;
+        self.logits_from_embeds(&image_embeds, &text_embeds)
+    }
+
+    // The contrastive head (L2 norm, logit-scale/bias, and anything a caller
+    // softmaxes downstream) runs in f32 even when the encoders themselves run
+    // in f16/bf16: this is the numerically sensitive part of the model and
+    // the accumulation error from reduced precision would otherwise show up
+    // directly in the reported probabilities.
+    fn logits_from_embeds(&self, image_embeds: &Tensor, text_embeds: &Tensor) -> Result<(Tensor, Tensor)> {
+        let image_embeds = div_l2_norm(&image_embeds.to_dtype(DType::F32)?)?;
+        let text_embeds = div_l2_norm(&text_embeds.to_dtype(DType::F32)?)?;
+        let logits_per_text = text_embeds.matmul(&image_embeds.t()?)?;
+        let logit_scale = self.logit_scale.to_dtype(DType::F32)?.exp()?;
+        let logit_bias = self.logit_bias.to_dtype(DType::F32)?;
+        let logits_per_text = logits_per_text
+            .broadcast_mul(&logit_scale)?
+            .broadcast_add(&logit_bias)?;
+        let logits_per_image = logits_per_text.t()?;
+        Ok((logits_per_text, logits_per_image))
+    }
+}
+
+pub fn div_l2_norm(v: &Tensor) -> Result<Tensor> {
+    let l2_norm = v.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+    v.broadcast_div(&l2_norm)
+}